@@ -1,4 +1,5 @@
 #![no_std]
+#![cfg_attr(feature = "allocator_api", feature(allocator_api))]
 //! `snmalloc-rs` provides a wrapper for [`microsoft/snmalloc`](https://github.com/microsoft/snmalloc) to make it usable as a global allocator for rust.
 //! snmalloc is a research allocator. Its key design features are:
 //! - Memory that is freed by the same thread that allocated it does not require any synchronising operations.
@@ -6,10 +7,11 @@
 //! - The allocator uses large ranges of pages to reduce the amount of meta-data required.
 //!
 //! The benchmark is available at the [paper](https://github.com/microsoft/snmalloc/blob/master/snmalloc.pdf) of `snmalloc`
-//! There are three features defined in this crate:
+//! There are four features defined in this crate:
 //! - `debug`: Enable the `Debug` mode in `snmalloc`.
 //! - `1mib`: Use the `1mib` chunk configuration.
 //! - `cache-friendly`: Make the allocator more cache friendly (setting `CACHE_FRIENDLY_OFFSET` to `64` in building the library).
+//! - `allocator_api`: Implement the nightly `core::alloc::Allocator` trait for [`SnMalloc`], so it can be used as a per-collection allocator (e.g. `Vec::new_in`, `Box::new_in`) rather than only as the `#[global_allocator]`.
 //!
 //! The whole library supports `no_std`.
 //!
@@ -30,6 +32,7 @@ extern crate snmalloc_sys as ffi;
 use core::{
     alloc::{GlobalAlloc, Layout},
     ptr::{self,NonNull},
+    sync::atomic::{AtomicPtr, Ordering},
 };
 
 #[derive(Debug, Copy, Clone)]
@@ -44,12 +47,39 @@ struct ZstSentinel;
 
 static ZST_SENTINEL: ZstSentinel = ZstSentinel;
 
+/// A callback invoked with the `Layout` of an allocation that snmalloc
+/// failed to satisfy. See [`SnMalloc::set_alloc_error_handler`].
+pub type AllocErrorHandler = fn(Layout);
+
+/// Stored as a type-erased function pointer so the hook stays `no_std` and
+/// lock-free (an `AtomicPtr` rather than a `Mutex<Option<AllocErrorHandler>>`).
+static ALLOC_ERROR_HANDLER: AtomicPtr<()> = AtomicPtr::new(ptr::null_mut());
+
+#[inline(always)]
+fn report_alloc_error(layout: Layout) {
+    let handler = ALLOC_ERROR_HANDLER.load(Ordering::Acquire);
+    if !handler.is_null() {
+        let handler: AllocErrorHandler = unsafe { core::mem::transmute(handler) };
+        handler(layout);
+    }
+}
+
 impl SnMalloc {
     #[inline(always)]
     pub const fn new() -> Self {
         Self
     }
 
+    /// Installs a callback invoked with the failing [`Layout`] whenever
+    /// [`safe_alloc`](Self::safe_alloc), [`safe_alloc_zeroed`](Self::safe_alloc_zeroed)
+    /// or [`safe_realloc`](Self::safe_realloc) fail to obtain memory from
+    /// snmalloc, mirroring `std::alloc::set_alloc_error_hook` while staying
+    /// `no_std`. The handler is called in addition to the default behavior:
+    /// once it returns, the null pointer is still propagated to the caller.
+    pub fn set_alloc_error_handler(handler: AllocErrorHandler) {
+        ALLOC_ERROR_HANDLER.store(handler as *mut (), Ordering::Release);
+    }
+
     #[inline(always)]
     fn handle_zst(&self) -> NonNull<u8> {
         unsafe { NonNull::new_unchecked(&ZST_SENTINEL as *const _ as *mut u8) }
@@ -60,7 +90,13 @@ impl SnMalloc {
     pub fn safe_alloc(&self, layout: Layout) -> Option<NonNull<u8>> {
         match layout.size() {
             0 => Some(self.handle_zst()),
-            size => NonNull::new(unsafe { ffi::sn_rust_alloc(layout.align(), size) }.cast()),
+            size => {
+                let ptr = NonNull::new(unsafe { ffi::sn_rust_alloc(layout.align(), size) }.cast());
+                if ptr.is_none() {
+                    report_alloc_error(layout);
+                }
+                ptr
+            }
         }
     }
 
@@ -69,10 +105,33 @@ impl SnMalloc {
     pub fn safe_alloc_zeroed(&self, layout: Layout) -> Option<NonNull<u8>> {
         match layout.size() {
             0 => Some(self.handle_zst()),
-            size => NonNull::new(unsafe { ffi::sn_rust_alloc_zeroed(layout.align(), size) }.cast()),
+            size => {
+                let ptr =
+                    NonNull::new(unsafe { ffi::sn_rust_alloc_zeroed(layout.align(), size) }.cast());
+                if ptr.is_none() {
+                    report_alloc_error(layout);
+                }
+                ptr
+            }
         }
     }
 
+    /// Allocates memory with the given layout, returning a slice sized to
+    /// the block's true usable capacity (as reported by snmalloc's size
+    /// class) rather than the requested size.
+    #[inline(always)]
+    pub fn safe_alloc_fitted(&self, layout: Layout) -> Option<NonNull<[u8]>> {
+        self.safe_alloc(layout).map(|ptr| fit_slice(ptr, layout.size()))
+    }
+
+    /// Allocates zero-initialized memory with the given layout, returning a
+    /// slice sized to the block's true usable capacity. See
+    /// [`safe_alloc_fitted`](Self::safe_alloc_fitted).
+    #[inline(always)]
+    pub fn safe_alloc_zeroed_fitted(&self, layout: Layout) -> Option<NonNull<[u8]>> {
+        self.safe_alloc_zeroed(layout).map(|ptr| fit_slice(ptr, layout.size()))
+    }
+
     /// Deallocates memory at the given pointer and layout.
     #[inline(always)]
     pub fn safe_dealloc(&self, ptr: *mut u8, layout: Layout) {
@@ -99,9 +158,24 @@ impl SnMalloc {
                 self.safe_dealloc(ptr, layout);
                 None // New size is zero; deallocate and return None.
             }
-            _ => NonNull::new(unsafe {
-                ffi::sn_rust_realloc(ptr.cast(), layout.align(), layout.size(), new_size).cast()
-            }),
+            // Alignment is unchanged across a realloc, so if the block's
+            // existing size class already covers the new size, snmalloc can
+            // keep serving the same pointer and skip the underlying
+            // allocator entirely (covers both growing into slack and
+            // shrinking within the same size class).
+            (_, _) if self.usable_size(ptr).is_some_and(|usable| new_size <= usable) => {
+                NonNull::new(ptr)
+            }
+            _ => {
+                let new_ptr = NonNull::new(unsafe {
+                    ffi::sn_rust_realloc(ptr.cast(), layout.align(), layout.size(), new_size)
+                        .cast()
+                });
+                if new_ptr.is_none() {
+                    report_alloc_error(Layout::from_size_align(new_size, layout.align()).ok()?);
+                }
+                new_ptr
+            }
         }
     }
     /// Allocates memory with the given layout, returning a non-null pointer on success
@@ -115,9 +189,105 @@ impl SnMalloc {
     /// Returns the usable size of an allocated block.
     #[inline(always)]
     pub fn usable_size(&self, ptr: *const u8) -> Option<usize> {
-        match ptr.is_null() {
-            true => None,
-            false => Some(unsafe { ffi::sn_rust_usable_size(ptr.cast()) }),
+        usable_size_of(ptr)
+    }
+}
+
+/// Queries the real usable size of an allocated block. This is independent
+/// of which allocator instance served it — snmalloc derives it from the
+/// block's address alone — so it is shared by [`SnMalloc`] and [`SnMallocPool`].
+#[inline(always)]
+fn usable_size_of(ptr: *const u8) -> Option<usize> {
+    match ptr.is_null() {
+        true => None,
+        false => Some(unsafe { ffi::sn_rust_usable_size(ptr.cast()) }),
+    }
+}
+
+/// Packages `ptr` into a fat pointer spanning its real usable size, falling
+/// back to `requested` (e.g. for the dangling ZST pointer, whose usable size
+/// is meaningless). Shared by [`SnMalloc`] and [`SnMallocPool`] so both
+/// `Allocator` impls return equally-fitted slices.
+#[inline(always)]
+fn fit_slice(ptr: NonNull<u8>, requested: usize) -> NonNull<[u8]> {
+    let size = match requested {
+        0 => 0,
+        _ => usable_size_of(ptr.as_ptr()).unwrap_or(requested),
+    };
+    NonNull::slice_from_raw_parts(ptr, size)
+}
+
+#[cfg(feature = "allocator_api")]
+unsafe impl core::alloc::Allocator for SnMalloc {
+    #[inline(always)]
+    fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, core::alloc::AllocError> {
+        match layout.size() {
+            0 => Ok(NonNull::slice_from_raw_parts(layout.dangling_ptr(), 0)),
+            _ => self.safe_alloc_fitted(layout).ok_or(core::alloc::AllocError),
+        }
+    }
+
+    #[inline(always)]
+    fn allocate_zeroed(&self, layout: Layout) -> Result<NonNull<[u8]>, core::alloc::AllocError> {
+        match layout.size() {
+            0 => Ok(NonNull::slice_from_raw_parts(layout.dangling_ptr(), 0)),
+            _ => self.safe_alloc_zeroed_fitted(layout).ok_or(core::alloc::AllocError),
+        }
+    }
+
+    #[inline(always)]
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+        self.safe_dealloc(ptr.as_ptr(), layout);
+    }
+
+    #[inline(always)]
+    unsafe fn grow(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Result<NonNull<[u8]>, core::alloc::AllocError> {
+        debug_assert!(new_layout.size() >= old_layout.size());
+        debug_assert_eq!(new_layout.align(), old_layout.align());
+        self.safe_realloc(ptr.as_ptr(), old_layout, new_layout.size())
+            .map(|ptr| fit_slice(ptr, new_layout.size()))
+            .ok_or(core::alloc::AllocError)
+    }
+
+    #[inline(always)]
+    unsafe fn grow_zeroed(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Result<NonNull<[u8]>, core::alloc::AllocError> {
+        let new_slice = self.grow(ptr, old_layout, new_layout)?;
+        let new_ptr = NonNull::new_unchecked(new_slice.as_ptr() as *mut u8);
+        new_ptr
+            .as_ptr()
+            .add(old_layout.size())
+            .write_bytes(0, new_layout.size() - old_layout.size());
+        Ok(new_slice)
+    }
+
+    #[inline(always)]
+    unsafe fn shrink(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Result<NonNull<[u8]>, core::alloc::AllocError> {
+        debug_assert!(new_layout.size() <= old_layout.size());
+        debug_assert_eq!(new_layout.align(), old_layout.align());
+        match new_layout.size() {
+            0 => {
+                self.safe_dealloc(ptr.as_ptr(), old_layout);
+                Ok(NonNull::slice_from_raw_parts(new_layout.dangling_ptr(), 0))
+            }
+            size => self
+                .safe_realloc(ptr.as_ptr(), old_layout, size)
+                .map(|ptr| fit_slice(ptr, size))
+                .ok_or(core::alloc::AllocError),
         }
     }
 }
@@ -146,6 +316,78 @@ unsafe impl GlobalAlloc for SnMalloc {
     }
 }
 
+/// A standalone snmalloc allocator handle, distinct from the process-wide
+/// `#[global_allocator]`.
+///
+/// snmalloc's native core supports many concurrent allocator instances, but
+/// `snmalloc-sys` does not yet expose a way to create or address one from
+/// Rust — it only wraps the single shared allocator behind
+/// `sn_rust_alloc`/`sn_rust_dealloc`/etc. Until that FFI surface lands
+/// upstream, `SnMallocPool` delegates to the same shared allocator as
+/// [`SnMalloc`]: it gives a subsystem a distinct Rust-level handle to code
+/// against today, and will gain real per-pool isolation with no API change
+/// once `snmalloc-sys` grows instance handles.
+#[derive(Debug, Default)]
+pub struct SnMallocPool {
+    _private: (),
+}
+
+impl SnMallocPool {
+    /// Creates a new pool handle.
+    pub fn new() -> Self {
+        Self { _private: () }
+    }
+
+    #[inline(always)]
+    fn handle_zst(&self) -> NonNull<u8> {
+        unsafe { NonNull::new_unchecked(&ZST_SENTINEL as *const _ as *mut u8) }
+    }
+
+    /// Allocates memory with the given layout from this pool.
+    #[inline(always)]
+    pub fn safe_alloc(&self, layout: Layout) -> Option<NonNull<u8>> {
+        match layout.size() {
+            0 => Some(self.handle_zst()),
+            size => NonNull::new(unsafe { ffi::sn_rust_alloc(layout.align(), size) }.cast()),
+        }
+    }
+
+    /// Allocates memory with the given layout from this pool, returning a
+    /// slice sized to the block's true usable capacity, matching
+    /// [`SnMalloc::safe_alloc_fitted`].
+    #[inline(always)]
+    pub fn safe_alloc_fitted(&self, layout: Layout) -> Option<NonNull<[u8]>> {
+        self.safe_alloc(layout).map(|ptr| fit_slice(ptr, layout.size()))
+    }
+
+    /// Deallocates memory at the given pointer and layout, within this pool.
+    #[inline(always)]
+    pub fn safe_dealloc(&self, ptr: *mut u8, layout: Layout) {
+        match (ptr.is_null(), layout.size()) {
+            (false, size) if size > 0 => unsafe {
+                ffi::sn_rust_dealloc(ptr.cast(), layout.align(), size);
+            },
+            _ => {} // No action needed for null pointers or ZSTs.
+        }
+    }
+}
+
+#[cfg(feature = "allocator_api")]
+unsafe impl core::alloc::Allocator for SnMallocPool {
+    #[inline(always)]
+    fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, core::alloc::AllocError> {
+        match layout.size() {
+            0 => Ok(NonNull::slice_from_raw_parts(layout.dangling_ptr(), 0)),
+            _ => self.safe_alloc_fitted(layout).ok_or(core::alloc::AllocError),
+        }
+    }
+
+    #[inline(always)]
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+        self.safe_dealloc(ptr.as_ptr(), layout);
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -229,7 +471,36 @@ mod tests {
             assert!(usz >= 8);
         }
     }
-    
+
+    #[test]
+    fn test_safe_realloc_skips_reallocation_within_usable_size() {
+        let alloc = SnMalloc::new();
+        let layout = Layout::from_size_align(8, 8).unwrap();
+
+        let ptr = alloc.safe_alloc(layout).expect("allocation failed");
+        let usable = alloc.usable_size(ptr.as_ptr()).expect("usable_size returned None");
+
+        // Requesting exactly the block's existing usable size must be served
+        // from the same pointer, without ever calling into sn_rust_realloc.
+        let new_ptr = alloc
+            .safe_realloc(ptr.as_ptr(), layout, usable)
+            .expect("realloc failed");
+        assert_eq!(ptr, new_ptr);
+
+        alloc.safe_dealloc(new_ptr.as_ptr(), Layout::from_size_align(usable, 8).unwrap());
+    }
+
+    #[test]
+    fn test_safe_alloc_fitted_returns_at_least_requested_size() {
+        let alloc = SnMalloc::new();
+        let layout = Layout::from_size_align(8, 8).unwrap();
+
+        let slice = alloc.safe_alloc_fitted(layout).expect("allocation failed");
+        assert!(slice.len() >= layout.size());
+
+        alloc.safe_dealloc(slice.as_ptr().cast(), Layout::from_size_align(slice.len(), 8).unwrap());
+    }
+
     #[test]
     fn test_zero_sized_allocation() {
         let alloc = SnMalloc::new();
@@ -242,4 +513,103 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_alloc_error_handler_invoked_on_failure() {
+        use core::sync::atomic::{AtomicBool, Ordering};
+
+        static CALLED: AtomicBool = AtomicBool::new(false);
+        fn handler(_layout: Layout) {
+            CALLED.store(true, Ordering::SeqCst);
+        }
+        SnMalloc::set_alloc_error_handler(handler);
+
+        let alloc = SnMalloc::new();
+        // A layout no real allocator can satisfy, to exercise the failure path.
+        let huge_layout = Layout::from_size_align(isize::MAX as usize - 7, 8).unwrap();
+        let result = alloc.safe_alloc(huge_layout);
+
+        // ALLOC_ERROR_HANDLER is a process-global static, so restore it to
+        // its default (no handler) regardless of outcome before asserting,
+        // or a later test's allocation failure would silently invoke it too.
+        ALLOC_ERROR_HANDLER.store(ptr::null_mut(), Ordering::SeqCst);
+
+        assert!(result.is_none());
+        assert!(CALLED.load(Ordering::SeqCst));
+    }
+
+    #[cfg(feature = "allocator_api")]
+    #[test]
+    fn test_allocator_api_allocate_returns_fitted_slice() {
+        use core::alloc::Allocator;
+
+        let alloc = SnMalloc::new();
+        let layout = Layout::from_size_align(8, 8).unwrap();
+
+        let slice = alloc.allocate(layout).expect("allocate failed");
+        assert!(slice.len() >= layout.size());
+
+        unsafe {
+            let ptr = NonNull::new(slice.as_ptr().cast()).unwrap();
+            alloc.deallocate(ptr, Layout::from_size_align(slice.len(), 8).unwrap());
+        }
+    }
+
+    #[cfg(feature = "allocator_api")]
+    #[test]
+    fn test_allocator_api_grow_returns_fitted_slice() {
+        use core::alloc::Allocator;
+
+        let alloc = SnMalloc::new();
+        let old_layout = Layout::from_size_align(8, 8).unwrap();
+        let new_layout = Layout::from_size_align(16, 8).unwrap();
+
+        let ptr = alloc.allocate(old_layout).expect("allocate failed");
+        let ptr = NonNull::new(ptr.as_ptr().cast()).unwrap();
+
+        let grown = unsafe {
+            alloc
+                .grow(ptr, old_layout, new_layout)
+                .expect("grow failed")
+        };
+        assert!(grown.len() >= new_layout.size());
+
+        unsafe {
+            let grown_ptr = NonNull::new(grown.as_ptr().cast()).unwrap();
+            alloc.deallocate(grown_ptr, Layout::from_size_align(grown.len(), 8).unwrap());
+        }
+    }
+
+    #[test]
+    fn pool_handles_many_allocations() {
+        const COUNT: usize = 4096;
+        let pool = SnMallocPool::new();
+        let layout = Layout::from_size_align(32, 8).unwrap();
+
+        let mut ptrs = [ptr::null_mut::<u8>(); COUNT];
+        for slot in ptrs.iter_mut() {
+            *slot = pool
+                .safe_alloc(layout)
+                .expect("pool allocation failed")
+                .as_ptr();
+        }
+        for &p in ptrs.iter() {
+            pool.safe_dealloc(p, layout);
+        }
+    }
+
+    #[test]
+    fn pool_and_global_allocator_coexist() {
+        let pool = SnMallocPool::new();
+        let global = SnMalloc::new();
+        let layout = Layout::from_size_align(16, 8).unwrap();
+
+        unsafe {
+            let pool_ptr = pool.safe_alloc(layout).expect("pool allocation failed");
+            let global_ptr = global.alloc(layout);
+
+            pool.safe_dealloc(pool_ptr.as_ptr(), layout);
+            global.dealloc(global_ptr, layout);
+        }
+    }
+
 }